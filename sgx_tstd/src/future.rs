@@ -29,13 +29,18 @@
 //! Asynchronous values.
 
 use core::cell::Cell;
-use core::marker::Unpin;
+use core::fmt;
+use core::marker::{Send, Unpin};
 use core::pin::Pin;
 use core::option::Option;
 use core::ptr::NonNull;
-use core::task::{LocalWaker, Poll};
+use core::task::{Context, Poll};
 use core::ops::{Drop, Generator, GeneratorState};
 
+use crate::sync::{Arc, Mutex, Condvar};
+use crate::task::{self, ArcWake};
+use alloc_crate::boxed::Box;
+
 #[doc(inline)]
 pub use core::future::*;
 
@@ -57,8 +62,8 @@ impl<T: Generator<Yield = ()>> !Unpin for GenFuture<T> {}
 
 impl<T: Generator<Yield = ()>> Future for GenFuture<T> {
     type Output = T::Return;
-    fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
-        set_task_waker(lw, || match unsafe { Pin::get_mut_unchecked(self).0.resume() } {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        set_task_context(cx, || match unsafe { Pin::get_mut_unchecked(self).0.resume() } {
             GeneratorState::Yielded(()) => Poll::Pending,
             GeneratorState::Complete(x) => Poll::Ready(x),
         })
@@ -66,58 +71,275 @@ impl<T: Generator<Yield = ()>> Future for GenFuture<T> {
 }
 
 thread_local! {
-    static TLS_WAKER: Cell<Option<NonNull<LocalWaker>>> = Cell::new(None);
+    static TLS_CX: Cell<Option<NonNull<Context<'static>>>> = Cell::new(None);
 }
 
-struct SetOnDrop(Option<NonNull<LocalWaker>>);
+struct SetOnDrop(Option<NonNull<Context<'static>>>);
 
 impl Drop for SetOnDrop {
     fn drop(&mut self) {
-        TLS_WAKER.with(|tls_waker| {
-            tls_waker.set(self.0.take());
+        TLS_CX.with(|tls_cx| {
+            tls_cx.set(self.0.take());
         });
     }
 }
 
 /// Sets the thread-local task context used by async/await futures.
-pub fn set_task_waker<F, R>(lw: &LocalWaker, f: F) -> R
+pub fn set_task_context<F, R>(cx: &mut Context<'_>, f: F) -> R
 where
     F: FnOnce() -> R
 {
-    let old_waker = TLS_WAKER.with(|tls_waker| {
-        tls_waker.replace(Some(NonNull::from(lw)))
+    // Safety: the `'static` lifetime here is a lie, but the original
+    // lifetime is restored by `get_task_context` before it is ever used,
+    // and `SetOnDrop` guarantees the pointer doesn't outlive this call.
+    let cx_ptr = NonNull::from(cx).cast::<Context<'static>>();
+    let old_cx = TLS_CX.with(|tls_cx| {
+        tls_cx.replace(Some(cx_ptr))
     });
-    let _reset_waker = SetOnDrop(old_waker);
+    let _reset_cx = SetOnDrop(old_cx);
     f()
 }
 
-/// Retrieves the thread-local task waker used by async/await futures.
+/// Retrieves the thread-local task context used by async/await futures.
 ///
-/// This function acquires exclusive access to the task waker.
+/// This function acquires exclusive access to the task context.
 ///
-/// Panics if no waker has been set or if the waker has already been
-/// retrieved by a surrounding call to get_task_waker.
-pub fn get_task_waker<F, R>(f: F) -> R
+/// Panics if no context has been set or if the context has already been
+/// retrieved by a surrounding call to get_task_context.
+pub fn get_task_context<F, R>(f: F) -> R
 where
-    F: FnOnce(&LocalWaker) -> R
+    F: FnOnce(&mut Context<'_>) -> R
 {
-    let waker_ptr = TLS_WAKER.with(|tls_waker| {
-        // Clear the entry so that nested `get_task_waker` calls
+    let cx_ptr = TLS_CX.with(|tls_cx| {
+        // Clear the entry so that nested `get_task_context` calls
         // will fail or set their own value.
-        tls_waker.replace(None)
+        tls_cx.replace(None)
     });
-    let _reset_waker = SetOnDrop(waker_ptr);
+    let _reset_cx = SetOnDrop(cx_ptr);
 
-    let waker_ptr = waker_ptr.expect(
-        "TLS LocalWaker not set. This is a rustc bug. \
+    let mut cx_ptr = cx_ptr.expect(
+        "TLS Context not set. This is a rustc bug. \
         Please file an issue on https://github.com/rust-lang/rust.");
-    unsafe { f(waker_ptr.as_ref()) }
+    unsafe { f(cx_ptr.as_mut()) }
 }
 
-/// Polls a future in the current thread-local task waker.
-pub fn poll_with_tls_waker<F>(f: Pin<&mut F>) -> Poll<F::Output>
+/// Polls a future in the current thread-local task context.
+pub fn poll_with_tls_context<F>(f: Pin<&mut F>) -> Poll<F::Output>
 where
     F: Future
 {
-    get_task_waker(|lw| F::poll(f, lw))
+    get_task_context(|cx| F::poll(f, cx))
+}
+
+/// The shared state behind the waker used by `block_on`.
+///
+/// `ready` is flipped to `true` by `wake()` and cleared by the polling loop
+/// once it has observed it, with both sides synchronized through `mutex` so
+/// a wake that lands between a `Pending` result and the thread parking is
+/// never lost.
+struct Parker {
+    mutex: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Parker {
+    fn new() -> Arc<Self> {
+        Arc::new(Parker {
+            mutex: Mutex::new(false),
+            condvar: Condvar::new(),
+        })
+    }
+
+    fn park(&self) {
+        let mut ready = self.mutex.lock().unwrap();
+        while !*ready {
+            ready = self.condvar.wait(ready).unwrap();
+        }
+        *ready = false;
+    }
+
+    fn unpark(&self) {
+        let mut ready = self.mutex.lock().unwrap();
+        *ready = true;
+        self.condvar.notify_one();
+    }
+}
+
+impl ArcWake for Parker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.unpark();
+    }
+}
+
+/// Runs a future to completion on the current thread.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    let parker = Parker::new();
+    let waker = task::waker(parker.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut f = f;
+    // Safety: `f` is shadowed so it can never again be moved or named by
+    // value, and the `Pin` does not outlive this stack frame.
+    let mut f = unsafe { Pin::new_unchecked(&mut f) };
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => parker.park(),
+        }
+    }
+}
+
+/// A custom trait object for representing an owned future.
+///
+/// This trait is implemented by `Pin<Box<F>>` and `Pin<&'a mut F>` for any
+/// future `F`, and is used to give `LocalFutureObj`/`FutureObj` a uniform
+/// way to store and later drop the future they own without knowing its
+/// concrete type.
+pub unsafe trait UnsafeFutureObj<'a, T>: 'a {
+    /// Convert this future into a raw, type-erased pointer.
+    fn into_raw(self) -> *mut (dyn Future<Output = T> + 'a);
+
+    /// Drop the future represented by the given raw pointer.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have been returned by a call to `into_raw` on an
+    /// object of the same type, and must not have been passed to `drop`
+    /// before.
+    unsafe fn drop(ptr: *mut (dyn Future<Output = T> + 'a));
+}
+
+unsafe impl<'a, T, F: Future<Output = T> + 'a> UnsafeFutureObj<'a, T> for Pin<Box<F>> {
+    fn into_raw(self) -> *mut (dyn Future<Output = T> + 'a) {
+        unsafe { Box::into_raw(Pin::into_inner_unchecked(self)) }
+    }
+
+    unsafe fn drop(ptr: *mut (dyn Future<Output = T> + 'a)) {
+        drop(Box::from_raw(ptr as *mut F));
+    }
+}
+
+unsafe impl<'a, T, F: Future<Output = T> + 'a> UnsafeFutureObj<'a, T> for Pin<&'a mut F> {
+    fn into_raw(self) -> *mut (dyn Future<Output = T> + 'a) {
+        unsafe { Pin::into_inner_unchecked(self) as *mut F }
+    }
+
+    unsafe fn drop(_ptr: *mut (dyn Future<Output = T> + 'a)) {
+        // The pointee is borrowed, not owned, so there is nothing to do.
+    }
+}
+
+/// An owned, type-erased future, not required to be `Send`.
+///
+/// This is the building block a task queue uses to hold a heterogeneous
+/// collection of boxed futures, e.g. in a `Vec<LocalFutureObj<'static, ()>>`.
+pub struct LocalFutureObj<'a, T> {
+    future: *mut (dyn Future<Output = T> + 'a),
+    drop_fn: unsafe fn(*mut (dyn Future<Output = T> + 'a)),
+}
+
+impl<'a, T> LocalFutureObj<'a, T> {
+    /// Create a `LocalFutureObj` from a custom trait object representation.
+    pub fn new<F: UnsafeFutureObj<'a, T> + 'a>(f: F) -> LocalFutureObj<'a, T> {
+        LocalFutureObj {
+            future: f.into_raw(),
+            drop_fn: F::drop,
+        }
+    }
+}
+
+impl<'a, T> fmt::Debug for LocalFutureObj<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalFutureObj").finish()
+    }
+}
+
+impl<'a, T> From<FutureObj<'a, T>> for LocalFutureObj<'a, T> {
+    fn from(f: FutureObj<'a, T>) -> LocalFutureObj<'a, T> {
+        f.0
+    }
+}
+
+impl<'a, T> Future for LocalFutureObj<'a, T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        unsafe {
+            let future = Pin::new_unchecked(&mut *Pin::get_mut_unchecked(self).future);
+            future.poll(cx)
+        }
+    }
+}
+
+impl<'a, T> Drop for LocalFutureObj<'a, T> {
+    fn drop(&mut self) {
+        unsafe { (self.drop_fn)(self.future) }
+    }
+}
+
+/// An owned, type-erased future that is `Send`.
+///
+/// This is the `Send` counterpart to `LocalFutureObj`, used wherever the
+/// future may be polled from a different thread than the one that created
+/// it, e.g. by a thread-pool-backed task queue.
+pub struct FutureObj<'a, T>(LocalFutureObj<'a, T>);
+
+unsafe impl<'a, T> Send for FutureObj<'a, T> {}
+
+impl<'a, T> FutureObj<'a, T> {
+    /// Create a `FutureObj` from a custom trait object representation.
+    pub fn new<F: UnsafeFutureObj<'a, T> + Send + 'a>(f: F) -> FutureObj<'a, T> {
+        FutureObj(LocalFutureObj::new(f))
+    }
+}
+
+impl<'a, T> fmt::Debug for FutureObj<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FutureObj").finish()
+    }
+}
+
+impl<'a, T> Future for FutureObj<'a, T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        unsafe { Pin::new_unchecked(&mut self.0).poll(cx) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::noop_waker;
+
+    struct ReadyFuture(i32);
+
+    impl Future for ReadyFuture {
+        type Output = i32;
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<i32> {
+            Poll::Ready(self.0)
+        }
+    }
+
+    #[test]
+    fn block_on_resolves_an_already_ready_future() {
+        assert_eq!(block_on(ReadyFuture(42)), 42);
+    }
+
+    #[test]
+    fn noop_waker_polls_a_future_once() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = ReadyFuture(7);
+        let fut = unsafe { Pin::new_unchecked(&mut fut) };
+        assert_eq!(fut.poll(&mut cx), Poll::Ready(7));
+    }
+
+    #[test]
+    fn future_obj_polls_a_boxed_future() {
+        let mut obj = FutureObj::new(Box::pin(ReadyFuture(9)));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(Pin::new(&mut obj).poll(&mut cx), Poll::Ready(9));
+    }
 }
@@ -219,13 +219,7 @@ pub mod time;
 pub mod enclave;
 pub mod untrusted;
 
-pub mod task {
-    //! Types and Traits for working with asynchronous tasks.
-    #[doc(inline)]
-    pub use core::task::*;
-    #[doc(inline)]
-    pub use alloc_crate::task::*;
-}
+pub mod task;
 
 pub mod future;
 
@@ -0,0 +1,395 @@
+// Copyright (C) 2017-2018 Baidu, Inc. All Rights Reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+//  * Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//  * Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in
+//    the documentation and/or other materials provided with the
+//    distribution.
+//  * Neither the name of Baidu, Inc., nor the names of its
+//    contributors may be used to endorse or promote products derived
+//    from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Types and Traits for working with asynchronous tasks.
+
+use core::fmt;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, RawWaker, RawWakerVTable, Waker, Poll};
+
+use alloc_crate::collections::VecDeque;
+use alloc_crate::vec::Vec;
+
+use crate::future::{FutureObj, LocalFutureObj};
+use crate::io;
+use crate::sync::{Arc, Mutex, Condvar};
+use crate::thread::{self, JoinHandle};
+
+#[doc(inline)]
+pub use core::task::*;
+#[doc(inline)]
+pub use alloc_crate::task::*;
+
+/// A way of waking up a specific task.
+///
+/// By implementing this trait, types that are expected to be wrapped in an
+/// `Arc` can be converted into `Waker` objects. Those Wakers can be used to
+/// signal a task that it should be polled again, without the caller having
+/// to hand-roll a `RawWakerVTable`.
+pub trait ArcWake {
+    /// Indicates that the associated task is ready to make progress and
+    /// should be polled.
+    ///
+    /// This function can be called from an arbitrary thread, including
+    /// threads that did not create the `ArcWake` based `Waker`.
+    fn wake(self: Arc<Self>) {
+        Self::wake_by_ref(&self)
+    }
+
+    /// Indicates that the associated task is ready to make progress and
+    /// should be polled. This function is like `wake`, but must not consume
+    /// the provided `Arc`.
+    fn wake_by_ref(arc_self: &Arc<Self>);
+}
+
+/// Creates a `Waker` from an `Arc<impl ArcWake>`.
+///
+/// The returned `Waker` will call `ArcWake::wake()` or `ArcWake::wake_by_ref()`
+/// whenever it is woken.
+pub fn waker<W>(wake: Arc<W>) -> Waker
+where
+    W: ArcWake + Send + Sync + 'static
+{
+    unsafe { Waker::from_raw(raw_waker(wake)) }
+}
+
+fn raw_waker<W>(wake: Arc<W>) -> RawWaker
+where
+    W: ArcWake + Send + Sync + 'static
+{
+    unsafe fn clone_raw<W: ArcWake + Send + Sync + 'static>(data: *const ()) -> RawWaker {
+        let arc = Arc::from_raw(data as *const W);
+        let cloned = arc.clone();
+        let _ = Arc::into_raw(arc);
+        raw_waker(cloned)
+    }
+
+    unsafe fn wake_raw<W: ArcWake + Send + Sync + 'static>(data: *const ()) {
+        let arc: Arc<W> = Arc::from_raw(data as *const W);
+        ArcWake::wake(arc);
+    }
+
+    unsafe fn wake_by_ref_raw<W: ArcWake + Send + Sync + 'static>(data: *const ()) {
+        let arc = mem::ManuallyDrop::new(Arc::from_raw(data as *const W));
+        ArcWake::wake_by_ref(&arc);
+    }
+
+    unsafe fn drop_raw<W: ArcWake + Send + Sync + 'static>(data: *const ()) {
+        drop(Arc::from_raw(data as *const W));
+    }
+
+    let ptr = Arc::into_raw(wake) as *const ();
+    let vtable = &RawWakerVTable::new(
+        clone_raw::<W>,
+        wake_raw::<W>,
+        wake_by_ref_raw::<W>,
+        drop_raw::<W>,
+    );
+    RawWaker::new(ptr, vtable)
+}
+
+const fn noop_raw_waker() -> RawWaker {
+    unsafe fn clone(_data: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    unsafe fn wake(_data: *const ()) {}
+    unsafe fn wake_by_ref(_data: *const ()) {}
+    unsafe fn drop(_data: *const ()) {}
+
+    static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+    RawWaker::new(core::ptr::null(), &NOOP_VTABLE)
+}
+
+/// Creates a `Waker` that does nothing when woken.
+pub fn noop_waker() -> Waker {
+    unsafe { Waker::from_raw(noop_raw_waker()) }
+}
+
+/// A `'static` reference to a `Waker` that does nothing when woken.
+pub fn noop_waker_ref() -> &'static Waker {
+    struct SyncRawWaker(RawWaker);
+    unsafe impl Sync for SyncRawWaker {}
+
+    static NOOP_WAKER: SyncRawWaker = SyncRawWaker(noop_raw_waker());
+
+    // Safety: `Waker` is `#[repr(transparent)]` around a single `RawWaker` field.
+    unsafe { &*(&NOOP_WAKER.0 as *const RawWaker as *const Waker) }
+}
+
+/// The result of a failed spawn.
+///
+/// Currently the only way a spawn can fail is if the executor has shut
+/// down, so that is the only way to construct one.
+pub struct SpawnError {
+    _priv: (),
+}
+
+impl SpawnError {
+    /// Create a `SpawnError` that represents an executor that is shut down.
+    pub fn shutdown() -> SpawnError {
+        SpawnError { _priv: () }
+    }
+
+    /// Whether this error represents an executor that is shut down.
+    pub fn is_shutdown(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpawnError").field("kind", &"shutdown").finish()
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot spawn a future onto a shut down executor")
+    }
+}
+
+/// A spawner for `Send` futures, i.e. futures that may run on any worker
+/// thread of the executor they are spawned onto.
+pub trait Spawn {
+    /// Spawn a future onto this executor's run-queue.
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError>;
+}
+
+/// A spawner for futures that are not `Send`, i.e. must stay on the thread
+/// that spawned them.
+pub trait LocalSpawn {
+    /// Spawn a future onto this executor's run-queue.
+    fn spawn_local_obj(&self, future: LocalFutureObj<'static, ()>) -> Result<(), SpawnError>;
+}
+
+/// A single queued unit of work: the future itself plus the run-queue it
+/// should be re-enqueued onto when woken.
+///
+/// `scheduled` guards against the task being placed onto the run-queue more
+/// than once at a time: it is set whenever the task is pushed onto the
+/// queue and cleared right before it is polled, so a wake that lands while
+/// the task is already queued (or already dequeued but not yet polled, and
+/// about to be polled anyway) is a no-op instead of a duplicate queue entry.
+/// A wake that lands while the task is being polled enqueues it again.
+struct Task {
+    future: Mutex<Option<FutureObj<'static, ()>>>,
+    queue: Arc<TaskQueue>,
+    scheduled: AtomicBool,
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if !arc_self.scheduled.swap(true, Ordering::AcqRel) {
+            let _ = arc_self.queue.enqueue(arc_self.clone());
+        }
+    }
+}
+
+/// The run-queue shared by an `EnclaveSpawner` and its worker threads.
+struct TaskQueue {
+    tasks: Mutex<VecDeque<Arc<Task>>>,
+    condvar: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl TaskQueue {
+    fn new() -> Arc<TaskQueue> {
+        Arc::new(TaskQueue {
+            tasks: Mutex::new(VecDeque::new()),
+            condvar: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        })
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Pushes `task` onto the queue, unless the queue has already been shut
+    /// down. The shutdown check and the push happen under the same lock so
+    /// callers never observe a successful enqueue that no worker will ever
+    /// drain.
+    fn enqueue(&self, task: Arc<Task>) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        if self.is_shut_down() {
+            return false;
+        }
+        tasks.push_back(task);
+        self.condvar.notify_one();
+        true
+    }
+
+    /// Waits for a task, or returns `None` once the queue has been shut
+    /// down and drained of the work that was already queued.
+    fn dequeue(&self) -> Option<Arc<Task>> {
+        let mut tasks = self.tasks.lock().unwrap();
+        loop {
+            if let Some(task) = tasks.pop_front() {
+                return Some(task);
+            }
+            if self.is_shut_down() {
+                return None;
+            }
+            tasks = self.condvar.wait(tasks).unwrap();
+        }
+    }
+
+    /// Marks the queue as shut down and wakes every worker blocked in
+    /// `dequeue` so it can observe the flag and exit.
+    fn shut_down(&self) {
+        let _tasks = self.tasks.lock().unwrap();
+        self.shutdown.store(true, Ordering::Release);
+        self.condvar.notify_all();
+    }
+}
+
+fn worker_loop(queue: Arc<TaskQueue>) {
+    while let Some(task) = queue.dequeue() {
+        let mut slot = task.future.lock().unwrap();
+        if let Some(mut future) = slot.take() {
+            // Clear `scheduled` right before polling, not right after
+            // dequeueing: a wake landing before this point would find the
+            // task already removed from the queue and is safe to drop,
+            // since the poll below is about to happen anyway.
+            task.scheduled.store(false, Ordering::Release);
+            let waker = waker(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Pending => *slot = Some(future),
+                Poll::Ready(()) => {}
+            }
+        }
+    }
+}
+
+/// A `Spawn` implementation that runs tasks on a fixed pool of enclave
+/// threads.
+///
+/// Spawned futures are placed on a shared run-queue; each worker thread
+/// pulls one off, polls it, and (if it returns `Pending`) puts it back to
+/// sleep until its waker re-enqueues it. Waking a task never polls it
+/// directly - it only makes the task visible to the next worker that goes
+/// looking for work, so `wake` can safely be called from any thread,
+/// including from inside an OCALL callback.
+///
+/// Dropping the spawner shuts the queue down and joins every worker thread,
+/// so no OCALL-backed thread outlives it. Tasks still sitting in the queue
+/// at that point are polled by the workers before they exit; a task woken
+/// after shutdown is not enqueued again.
+pub struct EnclaveSpawner {
+    queue: Arc<TaskQueue>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl EnclaveSpawner {
+    /// Create a new `EnclaveSpawner` backed by `num_threads` worker threads.
+    ///
+    /// If any worker thread fails to spawn, the workers started so far are
+    /// shut down and joined, and the originating `io::Error` is returned -
+    /// callers never get back a spawner with fewer workers than requested.
+    pub fn new(num_threads: usize) -> io::Result<EnclaveSpawner> {
+        let queue = TaskQueue::new();
+        let mut workers = Vec::with_capacity(num_threads);
+        for _ in 0..num_threads {
+            let worker_queue = queue.clone();
+            match thread::Builder::new().spawn(move || worker_loop(worker_queue)) {
+                Ok(handle) => workers.push(handle),
+                Err(err) => {
+                    queue.shut_down();
+                    for handle in workers {
+                        let _ = handle.join();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(EnclaveSpawner { queue, workers })
+    }
+}
+
+impl Drop for EnclaveSpawner {
+    fn drop(&mut self) {
+        self.queue.shut_down();
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Spawn for EnclaveSpawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            queue: self.queue.clone(),
+            scheduled: AtomicBool::new(true),
+        });
+        if self.queue.enqueue(task) {
+            Ok(())
+        } else {
+            Err(SpawnError::shutdown())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc_crate::boxed::Box;
+    use core::future::Future;
+
+    struct SetFlag {
+        flag: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Future for SetFlag {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            let (mutex, condvar) = &*self.flag;
+            let mut done = mutex.lock().unwrap();
+            *done = true;
+            condvar.notify_all();
+            Poll::Ready(())
+        }
+    }
+
+    #[test]
+    fn enclave_spawner_runs_a_spawned_future() {
+        let spawner = EnclaveSpawner::new(2).expect("worker threads should spawn");
+        let flag = Arc::new((Mutex::new(false), Condvar::new()));
+        let future = FutureObj::new(Box::pin(SetFlag { flag: flag.clone() }));
+        spawner.spawn_obj(future).expect("spawner should accept work");
+
+        let (mutex, condvar) = &*flag;
+        let mut done = mutex.lock().unwrap();
+        while !*done {
+            done = condvar.wait(done).unwrap();
+        }
+    }
+}